@@ -1,4 +1,16 @@
-use std::ops::{Deref, DerefMut};
+#![cfg_attr(not(feature = "use_std"), no_std)]
+
+// In `no_std` mode the compiler links `core` into the extern prelude on its
+// own, so declaring it here too would conflict (E0259). In `std` mode there's
+// no implicit `core` path on this edition, so it must be declared explicitly
+// for the `core::` imports below to resolve.
+#[cfg(feature = "use_std")]
+extern crate core;
+
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
 
 #[macro_export]
 macro_rules! defer {
@@ -7,30 +19,143 @@ macro_rules! defer {
     }
 }
 
+/// Defer some code that takes ownership of `data` until the end of the
+/// current scope.
+///
+/// Unlike `defer!`, the cleanup closure is handed `&mut data`, so it
+/// doesn't need to capture surrounding state by reference or reach for
+/// `RefCell` to get mutable access to it.
+#[macro_export]
+macro_rules! defer_on_value {
+    ($data:expr, $f:expr) => {
+        let mut _guard = $crate::guard($data, $f);
+    }
+}
+
+/// Controls in which situations a `Guard`'s closure should run.
+///
+/// Implement this to define a new running strategy for `Guard`. `Always`,
+/// `OnUnwind` and `OnSuccess` are the three strategies that come with this
+/// crate.
+pub trait Strategy {
+    /// Return `true` if the guard's dropfn should run.
+    fn should_run() -> bool;
+}
+
+/// Unconditionally run the guard's closure on scope exit (the default
+/// strategy).
+#[derive(Debug)]
+pub enum Always {}
+
+impl Strategy for Always {
+    #[inline]
+    fn should_run() -> bool { true }
+}
+
+/// Run the guard's closure only if the scope exits by unwinding (e.g. a
+/// panic), not on a normal return. This gives `errdefer`-style rollback
+/// that fires on error paths but leaves the success path untouched.
+///
+/// Requires the `use_std` feature, since detecting unwinding relies on
+/// `std::thread::panicking`.
+#[cfg(feature = "use_std")]
+#[derive(Debug)]
+pub enum OnUnwind {}
+
+#[cfg(feature = "use_std")]
+impl Strategy for OnUnwind {
+    #[inline]
+    fn should_run() -> bool { ::std::thread::panicking() }
+}
+
+/// Run the guard's closure only if the scope exits normally, not while
+/// unwinding (e.g. a panic).
+///
+/// Requires the `use_std` feature, since detecting unwinding relies on
+/// `std::thread::panicking`.
+#[cfg(feature = "use_std")]
+#[derive(Debug)]
+pub enum OnSuccess {}
+
+#[cfg(feature = "use_std")]
+impl Strategy for OnSuccess {
+    #[inline]
+    fn should_run() -> bool { !::std::thread::panicking() }
+}
+
 /// `Guard` is a scope guard that may own a protected value.
 ///
 /// If you place a guard value in a local variable, its destructor will
 /// run regardless how you leave the function — regular return or panic
-/// (barring truly abnormal incidents).
+/// (barring truly abnormal incidents). The exact circumstances under which
+/// the closure runs are determined by the `Strategy` parameter `S`.
 ///
 /// The guard's closure will be called with a mut ref to the held value
 /// in the destructor. It's called only once.
-pub struct Guard<T, F>
-    where F: FnMut(&mut T)
+pub struct Guard<T, F, S = Always>
+    where F: FnMut(&mut T), S: Strategy
 {
-    __dropfn: F,
-    __value: T,
+    __value: ManuallyDrop<T>,
+    __dropfn: ManuallyDrop<F>,
+    __strategy: PhantomData<S>,
 }
 
 /// Create a new `Guard` owning `v` and with deferred closure `dropfn`.
-pub fn guard<T, F>(v: T, dropfn: F) -> Guard<T, F>
+///
+/// The `dropfn` always runs when the guard is dropped, regular return or
+/// panic alike; it's the same as `guard_on_success` and `guard_on_unwind`
+/// combined.
+pub fn guard<T, F>(v: T, dropfn: F) -> Guard<T, F, Always>
     where F: FnMut(&mut T)
 {
-    Guard{__value: v, __dropfn: dropfn}
+    Guard{__value: ManuallyDrop::new(v), __dropfn: ManuallyDrop::new(dropfn), __strategy: PhantomData}
 }
 
-impl<T, F> Deref for Guard<T, F>
+/// Create a new `Guard` owning `v` whose closure runs only if the scope is
+/// exited by unwinding (e.g. a panic), mirroring `errdefer` in Zig/Go.
+#[cfg(feature = "use_std")]
+pub fn guard_on_unwind<T, F>(v: T, dropfn: F) -> Guard<T, F, OnUnwind>
     where F: FnMut(&mut T)
+{
+    Guard{__value: ManuallyDrop::new(v), __dropfn: ManuallyDrop::new(dropfn), __strategy: PhantomData}
+}
+
+/// Create a new `Guard` owning `v` whose closure runs only if the scope is
+/// exited normally, i.e. not while unwinding (e.g. a panic).
+#[cfg(feature = "use_std")]
+pub fn guard_on_success<T, F>(v: T, dropfn: F) -> Guard<T, F, OnSuccess>
+    where F: FnMut(&mut T)
+{
+    Guard{__value: ManuallyDrop::new(v), __dropfn: ManuallyDrop::new(dropfn), __strategy: PhantomData}
+}
+
+impl<T, F, S> Guard<T, F, S>
+    where F: FnMut(&mut T), S: Strategy
+{
+    /// Consume the guard, returning the held value and suppressing the
+    /// cleanup closure (it will not be called).
+    ///
+    /// Use this to "commit" instead of roll back:
+    /// `let g = guard(resource, |r| rollback(r)); ... let resource = g.into_inner();`
+    pub fn into_inner(self) -> T {
+        // Wrap `self` so its own `Drop` impl never runs, then move the
+        // closure and value out by hand: drop the closure in place
+        // (suppressing the cleanup) and read the value out.
+        let mut self_ = ManuallyDrop::new(self);
+        unsafe {
+            ManuallyDrop::drop(&mut self_.__dropfn);
+            ptr::read(&*self_.__value)
+        }
+    }
+
+    /// Alias for `into_inner`: cancel the cleanup and return the held value.
+    pub fn dismiss(self) -> T {
+        self.into_inner()
+    }
+}
+
+impl<T, F, S> Deref for Guard<T, F, S>
+    where F: FnMut(&mut T), S: Strategy
 {
     type Target = T;
     fn deref(&self) -> &T
@@ -40,8 +165,8 @@ impl<T, F> Deref for Guard<T, F>
 
 }
 
-impl<T, F> DerefMut for Guard<T, F>
-    where F: FnMut(&mut T)
+impl<T, F, S> DerefMut for Guard<T, F, S>
+    where F: FnMut(&mut T), S: Strategy
 {
     fn deref_mut(&mut self) -> &mut T
     {
@@ -49,20 +174,214 @@ impl<T, F> DerefMut for Guard<T, F>
     }
 }
 
-impl<T, F> Drop for Guard<T, F>
-    where F: FnMut(&mut T)
+impl<T, F, S> Drop for Guard<T, F, S>
+    where F: FnMut(&mut T), S: Strategy
 {
     fn drop(&mut self) {
-        (self.__dropfn)(&mut self.__value)
+        if S::should_run() {
+            (self.__dropfn)(&mut self.__value)
+        }
+        unsafe {
+            ManuallyDrop::drop(&mut self.__dropfn);
+            ManuallyDrop::drop(&mut self.__value);
+        }
     }
 }
 
-#[test]
-fn test_defer() {
-    use std::cell::Cell;
+/// A scope guard for cleanup that can itself fail, such as tearing down a
+/// handle to an external resource (deleting a remote key, closing a
+/// connection).
+///
+/// `Drop` can't return a `Result`, so any `Err` returned by the cleanup
+/// closure is routed at drop time to an error sink: either a handler
+/// installed with `on_cleanup_error`, or `eprintln!` by default. The sink
+/// is called inside a `catch_unwind`, so a panicking sink can never cause
+/// an abort while the guard itself is dropped during an unwind.
+///
+/// Requires the `use_std` feature.
+#[cfg(feature = "use_std")]
+pub struct ResultGuard<'a, T, F, E>
+    where F: FnMut(&mut T) -> Result<(), E>, E: ::std::fmt::Debug
+{
+    __value: T,
+    __dropfn: F,
+    __on_error: Option<Box<dyn FnMut(E) + 'a>>,
+}
 
-    let drops = Cell::new(0);
-    defer!(drops.set(1000));
-    assert_eq!(drops.get(), 0);
+/// Create a new `ResultGuard` owning `v`, whose cleanup closure may fail.
+///
+/// By default a failing cleanup is logged with `eprintln!`; install a
+/// different sink with `ResultGuard::on_cleanup_error`.
+#[cfg(feature = "use_std")]
+pub fn guard_with_result<'a, T, F, E>(v: T, dropfn: F) -> ResultGuard<'a, T, F, E>
+    where F: FnMut(&mut T) -> Result<(), E>, E: ::std::fmt::Debug
+{
+    ResultGuard{__value: v, __dropfn: dropfn, __on_error: None}
 }
 
+#[cfg(feature = "use_std")]
+impl<'a, T, F, E> ResultGuard<'a, T, F, E>
+    where F: FnMut(&mut T) -> Result<(), E>, E: ::std::fmt::Debug
+{
+    /// Install a handler to run if the cleanup closure returns `Err`,
+    /// replacing the default `eprintln!`-based sink.
+    pub fn on_cleanup_error<H>(mut self, handler: H) -> Self
+        where H: FnMut(E) + 'a
+    {
+        self.__on_error = Some(Box::new(handler));
+        self
+    }
+}
+
+#[cfg(feature = "use_std")]
+impl<'a, T, F, E> Deref for ResultGuard<'a, T, F, E>
+    where F: FnMut(&mut T) -> Result<(), E>, E: ::std::fmt::Debug
+{
+    type Target = T;
+    fn deref(&self) -> &T
+    {
+        &self.__value
+    }
+
+}
+
+#[cfg(feature = "use_std")]
+impl<'a, T, F, E> DerefMut for ResultGuard<'a, T, F, E>
+    where F: FnMut(&mut T) -> Result<(), E>, E: ::std::fmt::Debug
+{
+    fn deref_mut(&mut self) -> &mut T
+    {
+        &mut self.__value
+    }
+}
+
+#[cfg(feature = "use_std")]
+impl<'a, T, F, E> Drop for ResultGuard<'a, T, F, E>
+    where F: FnMut(&mut T) -> Result<(), E>, E: ::std::fmt::Debug
+{
+    fn drop(&mut self) {
+        if let Err(e) = (self.__dropfn)(&mut self.__value) {
+            let on_error = &mut self.__on_error;
+            // The sink runs inside `catch_unwind`: a cleanup failure must
+            // be surfaced, never allowed to abort the process by
+            // panicking while we're already unwinding.
+            let _ = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+                match on_error {
+                    Some(handler) => handler(e),
+                    None => eprintln!("scopeguard: error during cleanup: {:?}", e),
+                }
+            }));
+        }
+    }
+}
+
+#[cfg(all(test, feature = "use_std"))]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_defer() {
+        let drops = Cell::new(0);
+        defer!(drops.set(1000));
+        assert_eq!(drops.get(), 0);
+    }
+
+    #[test]
+    fn test_defer_on_value() {
+        let flag = Cell::new(false);
+        {
+            defer_on_value!(String::from("cleanup"), |s| flag.set(s == "cleanup"));
+        }
+        assert!(flag.get());
+    }
+
+    #[test]
+    fn test_dismiss() {
+        let drops = Cell::new(0);
+        {
+            let g = guard(&drops, |d| d.set(1));
+            let _ = g.into_inner();
+        }
+        assert_eq!(drops.get(), 0);
+    }
+
+    #[test]
+    fn test_guard_on_success() {
+        let drops = Cell::new(0);
+        {
+            let _guard = guard_on_success(&drops, |d| d.set(1));
+        }
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn test_guard_on_unwind() {
+        let drops = Cell::new(0);
+        {
+            let _guard = guard_on_unwind(&drops, |d| d.set(1));
+        }
+        assert_eq!(drops.get(), 0);
+    }
+
+    #[test]
+    fn test_guard_on_unwind_runs_on_panic() {
+        let drops = Cell::new(0);
+        let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            let _guard = guard_on_unwind(&drops, |d| d.set(1));
+            panic!("boom");
+        }));
+        assert!(result.is_err());
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn test_guard_on_success_skips_on_panic() {
+        let drops = Cell::new(0);
+        let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            let _guard = guard_on_success(&drops, |d| d.set(1));
+            panic!("boom");
+        }));
+        assert!(result.is_err());
+        assert_eq!(drops.get(), 0);
+    }
+
+    #[test]
+    fn test_guard_with_result_ok() {
+        let cleaned = Cell::new(false);
+        {
+            let _guard = guard_with_result(&cleaned, |c| -> Result<(), ()> {
+                c.set(true);
+                Ok(())
+            });
+        }
+        assert!(cleaned.get());
+    }
+
+    #[test]
+    fn test_guard_with_result_err_invokes_handler() {
+        let handled: Cell<Option<&str>> = Cell::new(None);
+        {
+            let _guard = guard_with_result((), |_| Err("boom"))
+                .on_cleanup_error(|e| handled.set(Some(e)));
+        }
+        assert_eq!(handled.get(), Some("boom"));
+    }
+
+    #[test]
+    fn test_guard_with_result_err_no_handler_falls_back_to_eprintln() {
+        let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            let _guard = guard_with_result((), |_| Err("boom"));
+        }));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_guard_with_result_handler_panic_does_not_escape_drop() {
+        let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            let _guard = guard_with_result((), |_| Err("boom"))
+                .on_cleanup_error(|_| panic!("handler also panics"));
+        }));
+        assert!(result.is_ok());
+    }
+}